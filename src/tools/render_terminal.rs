@@ -1,42 +1,164 @@
-use vte::{Parser, Perform};
+use vte::{Params, Parser, Perform};
 use std::collections::VecDeque;
+use unicode_width::UnicodeWidthChar;
+
+// Display width (in terminal cells) of a single character, as used for
+// wrapping and cursor advancement. Combining marks are 0, CJK/emoji are
+// 2, everything else is 1.
+fn char_width(c: char) -> usize {
+    UnicodeWidthChar::width(c).unwrap_or(1)
+}
 
 // Struct representing the screen for rendering terminal output
 struct Screen {
-    buffer: VecDeque<String>,
+    grid: VecDeque<Vec<String>>,
     width: usize,
     height: usize,
+    cursor_row: usize,
+    cursor_col: usize,
 }
 
 impl Screen {
     fn new(width: usize, height: usize) -> Self {
         Self {
-            buffer: VecDeque::from(vec![String::new(); height]),
+            grid: VecDeque::from(vec![vec![" ".to_string(); width]; height]),
             width,
             height,
+            cursor_row: 0,
+            cursor_col: 0,
         }
     }
 
     fn scroll_up(&mut self) {
-        self.buffer.pop_front();
-        self.buffer.push_back(String::new());
+        self.grid.pop_front();
+        self.grid.push_back(vec![" ".to_string(); self.width]);
+    }
+
+    fn new_line(&mut self) {
+        if self.cursor_row + 1 >= self.height {
+            self.scroll_up();
+        } else {
+            self.cursor_row += 1;
+        }
+    }
+
+    fn carriage_return(&mut self) {
+        self.cursor_col = 0;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor_col > 0 {
+            self.cursor_col -= 1;
+        }
     }
 
-    fn write_text(&mut self, text: &str) {
-        // Check if we need to scroll up before borrowing the line
-        if let Some(line) = self.buffer.back() {
-            if line.len() + text.len() > self.width {
-                self.scroll_up();
+    fn tab(&mut self) {
+        const TAB_STOP: usize = 8;
+        let next_stop = (self.cursor_col / TAB_STOP + 1) * TAB_STOP;
+        self.cursor_col = next_stop.min(self.width - 1);
+    }
+
+    fn write_char(&mut self, c: char) {
+        let w = char_width(c);
+        if w == 0 {
+            // Combining mark: fold into the previously written cell instead
+            // of consuming a column of its own.
+            if self.cursor_col > 0 {
+                self.grid[self.cursor_row][self.cursor_col - 1].push(c);
             }
+            return;
         }
-        if let Some(line) = self.buffer.back_mut() {
-            line.push_str(text);
+        if self.cursor_col + w > self.width {
+            self.carriage_return();
+            self.new_line();
+        }
+        self.grid[self.cursor_row][self.cursor_col] = c.to_string();
+        self.cursor_col += 1;
+        // Wide characters (e.g. CJK, emoji) occupy a second, empty cell so
+        // that column accounting matches the real terminal's display width.
+        for _ in 1..w {
+            if self.cursor_col >= self.width {
+                self.carriage_return();
+                self.new_line();
+            }
+            self.grid[self.cursor_row][self.cursor_col] = String::new();
+            self.cursor_col += 1;
+        }
+    }
+
+    fn move_cursor_up(&mut self, n: usize) {
+        self.cursor_row = self.cursor_row.saturating_sub(n);
+    }
+
+    fn move_cursor_down(&mut self, n: usize) {
+        self.cursor_row = (self.cursor_row + n).min(self.height - 1);
+    }
+
+    fn move_cursor_right(&mut self, n: usize) {
+        self.cursor_col = (self.cursor_col + n).min(self.width - 1);
+    }
+
+    fn move_cursor_left(&mut self, n: usize) {
+        self.cursor_col = self.cursor_col.saturating_sub(n);
+    }
+
+    fn move_cursor_to(&mut self, row: usize, col: usize) {
+        self.cursor_row = row.min(self.height - 1);
+        self.cursor_col = col.min(self.width - 1);
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        let row = &mut self.grid[self.cursor_row];
+        match mode {
+            0 => {
+                for cell in row.iter_mut().skip(self.cursor_col) {
+                    *cell = " ".to_string();
+                }
+            }
+            1 => {
+                for cell in row.iter_mut().take(self.cursor_col + 1) {
+                    *cell = " ".to_string();
+                }
+            }
+            2 => {
+                for cell in row.iter_mut() {
+                    *cell = " ".to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_in_display(&mut self, mode: u16) {
+        match mode {
+            0 => {
+                self.erase_in_line(0);
+                for row in self.grid.iter_mut().skip(self.cursor_row + 1) {
+                    row.iter_mut().for_each(|cell| *cell = " ".to_string());
+                }
+            }
+            1 => {
+                self.erase_in_line(1);
+                for row in self.grid.iter_mut().take(self.cursor_row) {
+                    row.iter_mut().for_each(|cell| *cell = " ".to_string());
+                }
+            }
+            2 => {
+                for row in self.grid.iter_mut() {
+                    row.iter_mut().for_each(|cell| *cell = " ".to_string());
+                }
+            }
+            _ => {}
         }
     }
 
     fn render(&self) -> String {
-        let filtered_lines: Vec<&String> = self
-            .buffer
+        let lines: Vec<String> = self
+            .grid
+            .iter()
+            .map(|row| row.concat().trim_end().to_string())
+            .collect();
+        let filtered_lines: Vec<&String> = lines
             .iter()
             .rev()
             .skip_while(|line| line.trim().is_empty())
@@ -69,26 +191,169 @@ impl TerminalEmulator {
     }
 }
 
+fn param_or(params: &Params, default: u16) -> u16 {
+    params
+        .iter()
+        .next()
+        .and_then(|p| p.first().copied())
+        .filter(|&v| v != 0)
+        .unwrap_or(default)
+}
+
 impl Perform for TerminalEmulator {
     fn print(&mut self, c: char) {
-        self.screen.write_text(&c.to_string());
+        self.screen.write_char(c);
     }
 
     fn execute(&mut self, byte: u8) {
-        // Handle line feed
-        if byte == b'\n' {
-            self.screen.scroll_up();
+        match byte {
+            b'\n' => self.screen.new_line(),
+            b'\r' => self.screen.carriage_return(),
+            b'\t' => self.screen.tab(),
+            0x08 => self.screen.backspace(),
+            _ => {}
         }
     }
 
+    fn csi_dispatch(&mut self, params: &Params, _intermediates: &[u8], _ignore: bool, action: char) {
+        match action {
+            'A' => self.screen.move_cursor_up(param_or(params, 1) as usize),
+            'B' => self.screen.move_cursor_down(param_or(params, 1) as usize),
+            'C' => self.screen.move_cursor_right(param_or(params, 1) as usize),
+            'D' => self.screen.move_cursor_left(param_or(params, 1) as usize),
+            'H' | 'f' => {
+                let mut iter = params.iter();
+                let row = iter
+                    .next()
+                    .and_then(|p| p.first().copied())
+                    .unwrap_or(1)
+                    .max(1) as usize
+                    - 1;
+                let col = iter
+                    .next()
+                    .and_then(|p| p.first().copied())
+                    .unwrap_or(1)
+                    .max(1) as usize
+                    - 1;
+                self.screen.move_cursor_to(row, col);
+            }
+            'J' => self.screen.erase_in_display(param_or(params, 0)),
+            'K' => self.screen.erase_in_line(param_or(params, 0)),
+            _ => {}
+        }
+    }
 }
 
-pub fn render_terminal_output(text: String) -> String {
-    // replace all \t with 4 spaces as it's not being rendered by the terminal emulator
-    let text = text.replace("\t", "    ");
-    let mut emulator = TerminalEmulator::new(160, 500);
+pub fn render_terminal_output(text: String, cols: usize, rows: usize) -> String {
+    let mut emulator = TerminalEmulator::new(cols, rows);
     emulator.feed(&text);
     let output = emulator.render();
     // trim all leading whitespace
     output.trim_start().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_width_ascii_is_one() {
+        assert_eq!(char_width('a'), 1);
+    }
+
+    #[test]
+    fn char_width_cjk_is_two() {
+        assert_eq!(char_width('中'), 2);
+        assert_eq!(char_width('文'), 2);
+    }
+
+    #[test]
+    fn char_width_emoji_is_two() {
+        assert_eq!(char_width('😀'), 2);
+    }
+
+    #[test]
+    fn char_width_combining_mark_is_zero() {
+        // U+0301 COMBINING ACUTE ACCENT
+        assert_eq!(char_width('\u{0301}'), 0);
+    }
+
+    #[test]
+    fn wraps_cjk_text_on_display_width_not_byte_count() {
+        // Each of these three CJK characters is 2 display columns wide, so a
+        // width-5 screen fits only two of them (4 columns) before wrapping,
+        // even though each character is 3 bytes in UTF-8.
+        let output = render_terminal_output("中文字".to_string(), 5, 2);
+        assert_eq!(output, "中文\n字");
+    }
+
+    #[test]
+    fn wraps_emoji_on_display_width() {
+        let output = render_terminal_output("😀😀😀".to_string(), 5, 2);
+        assert_eq!(output, "😀😀\n😀");
+    }
+
+    #[test]
+    fn ascii_wraps_at_exact_width() {
+        let output = render_terminal_output("abcde".to_string(), 3, 2);
+        assert_eq!(output, "abc\nde");
+    }
+
+    #[test]
+    fn cursor_home_via_h_moves_to_origin_and_overwrites() {
+        let output = render_terminal_output("hello\x1B[Hworld".to_string(), 5, 1);
+        assert_eq!(output, "world");
+    }
+
+    #[test]
+    fn cursor_position_via_f_uses_explicit_row_and_col() {
+        // "\x1B[2;3f" moves to the 1-indexed row 2, col 3, i.e. row 1 / col 2.
+        let output = render_terminal_output("\x1B[2;3fX".to_string(), 5, 3);
+        assert_eq!(output, "X");
+    }
+
+    #[test]
+    fn erase_in_line_mode_0_clears_from_cursor_to_end() {
+        let output = render_terminal_output("abcde\x1B[1;3H\x1B[0K".to_string(), 5, 1);
+        assert_eq!(output, "ab");
+    }
+
+    #[test]
+    fn erase_in_line_mode_1_clears_from_start_to_cursor() {
+        let output = render_terminal_output("abcde\x1B[1;3H\x1B[1K".to_string(), 5, 1);
+        assert_eq!(output, "de");
+    }
+
+    #[test]
+    fn erase_in_line_mode_2_clears_entire_line() {
+        let output = render_terminal_output("abcde\x1B[2K".to_string(), 5, 1);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn erase_in_display_mode_0_clears_cursor_to_end_of_screen() {
+        let text = "11111\r\n22222\r\n33333\x1B[2;3H\x1B[0J";
+        let output = render_terminal_output(text.to_string(), 5, 3);
+        assert_eq!(output, "11111\n22");
+    }
+
+    #[test]
+    fn erase_in_display_mode_1_clears_start_of_screen_to_cursor() {
+        let text = "11111\r\n22222\r\n33333\x1B[2;3H\x1B[1J";
+        let output = render_terminal_output(text.to_string(), 5, 3);
+        assert_eq!(output, "22\n33333");
+    }
+
+    #[test]
+    fn erase_in_display_mode_2_clears_entire_screen() {
+        let text = "11111\r\n22222\r\n33333\x1B[2J";
+        let output = render_terminal_output(text.to_string(), 5, 3);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn scrolls_earliest_line_off_when_output_exceeds_height() {
+        let output = render_terminal_output("one\r\ntwo\r\nthree".to_string(), 5, 2);
+        assert_eq!(output, "two\nthree");
+    }
+}
@@ -1,9 +1,12 @@
+use regex::Regex;
 use rexpect::errors::ErrorKind::Timeout;
 use rexpect::errors::{Error as RexpectError, Result, ResultExt};
 use rexpect::session::{PtyReplSession, PtySession};
 use rexpect::{spawn, spawn_bash};
 use std::fmt::Display;
 use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tiktoken_rs::CoreBPE;
 
 use super::render_terminal::render_terminal_output;
@@ -25,18 +28,76 @@ pub struct Shell {
     state: BashState,
     logger: Box<dyn Logger>,
     tokenizer: CoreBPE,
+    rows: u16,
+    cols: u16,
+    raw_log: Option<Box<dyn Write>>,
+    marker_regex: Regex,
 }
 
 pub struct Config {
     timeout: u64,
+    rows: u16,
+    cols: u16,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { timeout: 5000 }
+        Config {
+            timeout: 5000,
+            rows: 24,
+            cols: 160,
+        }
+    }
+}
+
+// Apply a window size to the given PTY master fd, so that programs querying
+// TIOCGWINSZ (columnized `ls`, `less`, `$COLUMNS`-aware scripts) see the same
+// dimensions we use to render their output.
+fn set_pty_size(fd: i32, rows: u16, cols: u16) -> std::io::Result<()> {
+    let winsize = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) };
+    if ret == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
     }
 }
 
+// Matches an ANSI/CSI escape sequence (ESC '[' ... final byte), so it can be
+// allowed to appear between the tokens of the sentinel marker without
+// breaking the match. Used only inside `marker_pattern`; everywhere else in
+// the output, escape sequences are left for `render_terminal_output` to
+// interpret.
+const ANSI_GAP: &str = r"(?:\x1B\[[0-?]*[ -/]*[@-~])*";
+
+// Builds the marker regex from the literal sentinel, tolerating stray ANSI
+// escapes between its tokens. Capturing and stripping the marker both use
+// this single regex against the same (raw) text, so they can never disagree
+// on where the marker is.
+fn marker_pattern(sentinel: &str) -> String {
+    let sentinel = regex::escape(sentinel);
+    format!(
+        "{sentinel}{gap}:{gap}(\\d+){gap}:{gap}{sentinel}",
+        sentinel = sentinel,
+        gap = ANSI_GAP
+    )
+}
+
+// A random-enough per-session token that cannot plausibly appear in normal
+// command output, used to delimit commands and carry their exit code.
+fn generate_sentinel() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("wcgw-{:x}-{:x}", std::process::id(), nanos)
+}
+
 #[derive(Debug)]
 pub enum ShellError {
     RexpectError(RexpectError),
@@ -91,14 +152,91 @@ impl Shell {
         tokenizer: CoreBPE,
     ) -> std::result::Result<Self, ShellError> {
         let mut session = spawn_bash(Some(config.timeout))?;
+        let fd = session.writer.get_ref().as_raw_fd();
+        set_pty_size(fd, config.rows, config.cols)
+            .chain_err(|| "cannot set initial pty window size")?;
+
+        let sentinel = generate_sentinel();
+        // Every prompt is now preceded by an unambiguous marker line carrying
+        // the previous command's exit code, so `wait_for_output` no longer
+        // has to send a second `echo $?` and race its own output.
+        session.send_line(&format!(
+            "PROMPT_COMMAND='echo \"{sentinel}:$?:{sentinel}\"'",
+            sentinel = sentinel
+        ))?;
+        session.wait_for_prompt()?;
+        let marker_regex =
+            Regex::new(&marker_pattern(&sentinel)).chain_err(|| "cannot compile sentinel marker regex")?;
+
         Ok(Shell {
             session,
             state: BashState::Idle,
             logger,
             tokenizer,
+            rows: config.rows,
+            cols: config.cols,
+            raw_log: None,
+            marker_regex,
         })
     }
 
+    /// Tees every byte written to and read from the child, timestamped and
+    /// direction-tagged (`>>` for input, `<<` for output), to `writer`.
+    /// Mirrors expectrl's `set_log` ergonomics for diagnosing prompt
+    /// detection and escape-sequence issues.
+    pub fn with_log(mut self, writer: Box<dyn Write>) -> Self {
+        self.raw_log = Some(writer);
+        self
+    }
+
+    fn log_raw(&mut self, direction: &str, bytes: &[u8]) {
+        if let Some(log) = self.raw_log.as_mut() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0);
+            let _ = writeln!(
+                log,
+                "[{}] {} {:?}",
+                timestamp,
+                direction,
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    fn send_line(&mut self, cmd: &str) -> Result<usize> {
+        self.log_raw(">>", cmd.as_bytes());
+        self.log_raw(">>", b"\n");
+        self.session.send_line(cmd)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> std::result::Result<(), ShellError> {
+        self.log_raw(">>", bytes);
+        self.session
+            .writer
+            .write_all(bytes)
+            .chain_err(|| "cannot write line to process")
+            .map_err(ShellError::from)
+    }
+
+    /// Re-applies the PTY window size for long-lived sessions, notifying the
+    /// child with `SIGWINCH` so it can re-query `TIOCGWINSZ` and redraw.
+    pub fn resize(&mut self, rows: u16, cols: u16) -> std::result::Result<(), ShellError> {
+        let fd = self.session.writer.get_ref().as_raw_fd();
+        set_pty_size(fd, rows, cols).chain_err(|| "cannot set pty window size")?;
+        let pid = self.session.process.child_pid.as_raw();
+        let ret = unsafe { libc::kill(pid, libc::SIGWINCH) };
+        if ret == -1 {
+            return Err(ShellError::ShellWorkflowError(
+                std::io::Error::last_os_error().to_string(),
+            ));
+        }
+        self.rows = rows;
+        self.cols = cols;
+        Ok(())
+    }
+
     pub fn execute_command(
         &mut self,
         command: Option<&str>,
@@ -118,48 +256,20 @@ impl Shell {
                 return Err(ShellError::ShellWorkflowError("Command should not contain newline character in middle. Run only one command at a time.".to_owned()));
             }
             self.logger.log(&format!("$ {}", cmd));
-            self.session.send_line(cmd)?;
+            self.send_line(cmd)?;
         } else if let Some(AsciiOrSpecial::Ascii(ascii)) = send_ascii {
-            for ch in ascii {
-                self.session
-                    .writer
-                    .write(&[ch])
-                    .chain_err(|| "cannot write line to process")?;
-            }
+            self.write_bytes(&ascii)?;
             self.session.flush()?;
         } else if let Some(AsciiOrSpecial::Special(special)) = send_ascii {
-            match special {
-                Specials::KeyUp => self
-                    .session
-                    .writer
-                    .write("\x1B[A".as_bytes())
-                    .chain_err(|| "cannot write line to process")?,
-                Specials::KeyDown => self
-                    .session
-                    .writer
-                    .write("\x1B[B".as_bytes())
-                    .chain_err(|| "cannot write line to process")?,
-                Specials::KeyLeft => self
-                    .session
-                    .writer
-                    .write("\x1B[D".as_bytes())
-                    .chain_err(|| "cannot write line to process")?,
-                Specials::KeyRight => self
-                    .session
-                    .writer
-                    .write("\x1B[C".as_bytes())
-                    .chain_err(|| "cannot write line to process")?,
-                Specials::Enter => self
-                    .session
-                    .writer
-                    .write("\n".as_bytes())
-                    .chain_err(|| "cannot write line to process")?,
-                Specials::CtrlC => self
-                    .session
-                    .writer
-                    .write(&[3])
-                    .chain_err(|| "cannot write line to process")?,
+            let bytes: &[u8] = match special {
+                Specials::KeyUp => b"\x1B[A",
+                Specials::KeyDown => b"\x1B[B",
+                Specials::KeyLeft => b"\x1B[D",
+                Specials::KeyRight => b"\x1B[C",
+                Specials::Enter => b"\n",
+                Specials::CtrlC => &[3],
             };
+            self.write_bytes(bytes)?;
             self.session.flush()?;
         } else {
             return Err(ShellError::ShellWorkflowError(
@@ -170,11 +280,71 @@ impl Shell {
         self.wait_for_output()
     }
 
+    /// Non-blockingly checks on a command left running by a prior timeout
+    /// (see `WAITING_FOR_INPUT_ERROR`). Drains whatever bytes the PTY has
+    /// produced since the last check and reports whether the prompt has now
+    /// reappeared (command finished) or the command is still `(pending)`,
+    /// without sending any new input. Lets an agent monitor a long-running
+    /// build/server/test suite instead of blindly waiting or interrupting it.
+    ///
+    /// `rexpect`'s reader timeout is fixed at session construction, so this
+    /// can't just delegate to `wait_for_prompt` with a different deadline.
+    /// Instead it drains the reader itself with `try_read`, which (unlike
+    /// `read_until`) pops characters out of the reader's internal buffer one
+    /// at a time, so each call only ever sees bytes produced since the
+    /// previous poll.
+    pub fn poll_output(&mut self, timeout_ms: u64) -> std::result::Result<String, ShellError> {
+        if self.state != BashState::WaitingForInput {
+            return Err(ShellError::ShellWorkflowError(
+                "No command is currently running, nothing to poll.".to_owned(),
+            ));
+        }
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mut chunk = String::new();
+        loop {
+            match self.session.try_read() {
+                Some(c) => chunk.push(c),
+                None if Instant::now() >= deadline => break,
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+            if self.marker_regex.is_match(&chunk) {
+                break;
+            }
+        }
+        self.log_raw("<<", chunk.as_bytes());
+        match self.marker_regex.captures(&chunk) {
+            Some(captures) => {
+                self.state = BashState::Idle;
+                let err_code: i32 = captures[1]
+                    .parse()
+                    .chain_err(|| "sentinel marker did not carry a valid exit code")?;
+                // Same regex, same string: capture and removal can't disagree
+                // on where the marker is.
+                let chunk = self.marker_regex.replace(&chunk, "").into_owned();
+                let output = self.truncate_output(render_terminal_output(
+                    chunk,
+                    self.cols as usize,
+                    self.rows as usize,
+                ))?;
+                Ok(format!("{}\n(exit {})", output, err_code))
+            }
+            None => {
+                let output = self.truncate_output(render_terminal_output(
+                    chunk,
+                    self.cols as usize,
+                    self.rows as usize,
+                ))?;
+                Ok(format!("{}\n(pending)", output))
+            }
+        }
+    }
+
     fn wait_for_output(&mut self) -> std::result::Result<String, ShellError> {
         let expected: Result<String> = self.session.wait_for_prompt();
         match expected {
             Err(RexpectError(error_kind, state)) => match error_kind {
                 Timeout(_, output, _) => {
+                    self.log_raw("<<", output.as_bytes());
                     self.state = BashState::WaitingForInput;
                     let output = self.truncate_output(output)?;
                     let last_line = "(pending)";
@@ -185,11 +355,22 @@ impl Shell {
                 }
             },
             Ok(output) => {
+                self.log_raw("<<", output.as_bytes());
                 self.state = BashState::Idle;
                 println!("output: {:?}", output);
-                let output = self.truncate_output(render_terminal_output(output))?;
+                let captures = self.marker_regex.captures(&output).ok_or_else(|| {
+                    ShellError::ShellWorkflowError(
+                        "could not find sentinel marker in command output".to_owned(),
+                    )
+                })?;
+                let err_code: i32 = captures[1]
+                    .parse()
+                    .chain_err(|| "sentinel marker did not carry a valid exit code")?;
+                // Same regex, same string: capture and removal can't disagree
+                // on where the marker is.
+                let output = self.marker_regex.replace(&output, "").into_owned();
+                let output = self.truncate_output(render_terminal_output(output, self.cols as usize, self.rows as usize))?;
                 println!("output: {}", output);
-                let err_code = self.get_exit_code()?;
                 let output = format!("{}\n(exit {})", output, err_code);
                 return Ok(output);
             }
@@ -209,18 +390,4 @@ impl Shell {
         }
     }
 
-    pub fn get_exit_code(&mut self) -> Result<i32> {
-        self.session.send_line("echo $?")?;
-        let mut before = String::new();
-
-        loop {
-            match before.trim().parse::<i32>() {
-                Err(_) => {
-                    println!("before: {:?}", before);
-                    before = render_terminal_output(self.session.wait_for_prompt()?);
-                }
-                Ok(val) => return Ok(val),
-            }
-        }
-    }
 }